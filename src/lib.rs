@@ -1,17 +1,264 @@
 //! Middleware to provide XSRF protection.
+//!
+//! # Token submission channels
+//!
+//! A client submits its token back via [`ProtectXSRFBuilder::header_name`]
+//! (an `X-CSRF-Token`-style header, set from JS) and, if explicitly enabled
+//! with [`ProtectXSRFBuilder::query_param_name`], a URL query parameter —
+//! there is no body-form-field channel, since verification runs ahead of
+//! body parsing and this crate has no way to read one out of the request.
+//! The query parameter is disabled by default because putting the token in
+//! the URL leaks it into server/proxy access logs, browser history, and to
+//! any third party the resulting page loads a resource from, via the
+//! `Referer` header — exactly what the masked double-submit pattern is
+//! meant to avoid. Only enable it if you specifically need to support a
+//! plain HTML form whose action URL can carry the token.
+//!
+//! # Per-route opt-out
+//!
+//! There is no route-declared marker (e.g. `app_data`) for opting a
+//! specific resource out of verification while [`ProtectXSRF`] is
+//! installed via `App::wrap`. actix-web routes a request — and resolves
+//! any per-resource `app_data` — *inside* the service chain that
+//! `App::wrap` wraps, so by the time a resource-level marker exists on the
+//! request, the outer `ProtectXSRF::call` has already run its check; it
+//! cannot see markers attached further in.
+//!
+//! Two approaches do work:
+//! - [`ProtectXSRFBuilder::bypass`] registers a path/method pair to skip,
+//!   checked against the request before routing happens, so it doesn't hit
+//!   this problem — at the cost of keeping a path string in sync with the
+//!   route instead of declaring the opt-out at the route itself.
+//! - Apply `ProtectXSRF` per-resource via `Resource::wrap`/`Route::wrap`
+//!   instead of `App::wrap`, and simply leave routes that should skip
+//!   verification unwrapped. This protects only the resources you wrap,
+//!   rather than everything except an opt-out list.
 
-use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::cookie::Key;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::http::HeaderValue;
-use actix_web::http::{self, CookieBuilder};
+use actix_web::http::{self, CookieBuilder, SameSite};
 use actix_web::HttpMessage;
-use actix_web::{Error, HttpRequest};
+use actix_web::{Error, FromRequest, HttpRequest, HttpResponse, ResponseError};
 use futures_util::future::{ok, FutureExt, LocalBoxFuture, Ready};
+use ring::hmac;
 use std::convert::TryInto;
+use std::fmt;
 use std::task::{Context, Poll};
 use xsrf::{CookieToken, RequestToken};
 
 const MIDDLEWARE_MISSING: &str = "xsrf_token used without corresponding middleware";
 
+/// Default set of methods assumed not to mutate state, and so let through
+/// without a submitted token.
+fn default_safe_methods() -> Vec<http::Method> {
+    vec![
+        http::Method::GET,
+        http::Method::HEAD,
+        http::Method::OPTIONS,
+        http::Method::TRACE,
+    ]
+}
+
+fn is_safe_method(config: &ProtectXSRF, method: &http::Method) -> bool {
+    config.safe_methods.iter().any(|m| m == method)
+}
+
+/// A path pattern registered via [`ProtectXSRFBuilder::bypass`]. A pattern
+/// ending in `*` matches by prefix; anything else must match exactly.
+#[derive(Clone, Copy)]
+enum PathPattern {
+    Exact(&'static str),
+    Prefix(&'static str),
+}
+
+impl PathPattern {
+    fn parse(pattern: &'static str) -> Self {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => PathPattern::Prefix(prefix),
+            None => PathPattern::Exact(pattern),
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Exact(p) => path == *p,
+            PathPattern::Prefix(p) => path.starts_with(p),
+        }
+    }
+}
+
+/// Whether `req` matches one of `config`'s registered bypass rules, in
+/// which case verification is skipped entirely.
+fn is_bypassed(config: &ProtectXSRF, req: &ServiceRequest) -> bool {
+    config.bypass.iter().any(|(method, pattern)| {
+        method.as_ref().map_or(true, |m| m == req.method()) && pattern.matches(req.path())
+    })
+}
+
+/// Pulls `name` out of the request's query string, if present.
+///
+/// Submitted tokens are expected to be URL-safe (e.g. base64url), so no
+/// percent-decoding is performed.
+fn query_param<'a>(req: &'a ServiceRequest, name: &str) -> Option<&'a str> {
+    req.uri().query().and_then(|query| {
+        query.split('&').find_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            if parts.next()? == name {
+                Some(parts.next().unwrap_or(""))
+            } else {
+                None
+            }
+        })
+    })
+}
+
+/// Extracts the token submitted via `config.header_name`, if present.
+fn get_header_token(req: &ServiceRequest, config: &ProtectXSRF) -> Option<RequestToken> {
+    req.headers()
+        .get(config.header_name)?
+        .to_str()
+        .ok()?
+        .try_into()
+        .ok()
+}
+
+/// Extracts the token submitted with `req`, per `config`. The header takes
+/// precedence over the query parameter (when enabled via
+/// [`ProtectXSRFBuilder::query_param_name`]), so AJAX clients that set both
+/// still get a consistent answer.
+fn get_submitted_token(req: &ServiceRequest, config: &ProtectXSRF) -> Option<RequestToken> {
+    get_header_token(req, config).or_else(|| {
+        let name = config.query_param_name?;
+        query_param(req, name)?.try_into().ok()
+    })
+}
+
+/// Why a request failed XSRF verification.
+#[derive(Debug)]
+pub enum CsrfError {
+    /// The request carried no XSRF cookie to verify against.
+    MissingCookie,
+    /// No token was submitted via the configured header or query parameter.
+    MissingToken,
+    /// A token was submitted, but it wasn't derived from the request's
+    /// XSRF cookie.
+    TokenMismatch,
+}
+
+impl fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsrfError::MissingCookie => write!(f, "missing xsrf cookie"),
+            CsrfError::MissingToken => write!(f, "missing xsrf token"),
+            CsrfError::TokenMismatch => write!(f, "xsrf token mismatch"),
+        }
+    }
+}
+
+impl ResponseError for CsrfError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::Forbidden().body(self.to_string())
+    }
+}
+
+/// Verifies that `req` carries a submitted token derived from its XSRF
+/// cookie. Returns `Err` describing the failure when it does not.
+fn verify_request(req: &ServiceRequest, config: &ProtectXSRF) -> Result<(), CsrfError> {
+    let cookie_token: CookieToken = req
+        .cookie(config.cookie_name)
+        .ok_or(CsrfError::MissingCookie)?
+        .value()
+        .try_into()
+        .map_err(|_| CsrfError::MissingCookie)?;
+    let request_token = get_submitted_token(req, config).ok_or(CsrfError::MissingToken)?;
+    let verified = match &config.mode {
+        TokenMode::DoubleSubmit => cookie_token.verify(&request_token),
+        TokenMode::Signed(key) => {
+            let submitted =
+                base64::decode_config(request_token.to_string(), base64::URL_SAFE_NO_PAD)
+                    .map_err(|_| CsrfError::TokenMismatch)?;
+            let identity = identity_of(req);
+            verify_signed(key, &cookie_token, identity.as_deref(), &submitted)
+        }
+    };
+    if verified {
+        Ok(())
+    } else {
+        Err(CsrfError::TokenMismatch)
+    }
+}
+
+/// Whether `ProtectXSRF` mints a token purely from the double-submit
+/// cookie, or an HMAC signed with a server-side secret.
+#[derive(Clone)]
+enum TokenMode {
+    /// The masked double-submit pattern: the submitted token only needs to
+    /// be derivable from the cookie, with no server-side secret involved.
+    DoubleSubmit,
+    /// The submitted token is an HMAC over the cookie token and, if
+    /// present, the request's [`XsrfIdentity`] — so a token minted for one
+    /// user can't be replayed by another.
+    Signed(Key),
+}
+
+/// Per-user identity bound into a [`TokenMode::Signed`] token. Insert this
+/// into request extensions (e.g. from an authentication middleware) before
+/// `ProtectXSRF` runs, and a minted token will only verify for the same
+/// identity.
+pub struct XsrfIdentity(pub String);
+
+fn identity_of(req: &ServiceRequest) -> Option<String> {
+    req.extensions()
+        .get::<XsrfIdentity>()
+        .map(|identity| identity.0.clone())
+}
+
+/// The bytes signed/verified for `cookie_token` and `identity` in
+/// [`TokenMode::Signed`] mode.
+fn signed_message(cookie_token: &CookieToken, identity: Option<&str>) -> Vec<u8> {
+    let mut message = cookie_token.to_string().into_bytes();
+    if let Some(identity) = identity {
+        message.push(b'|');
+        message.extend_from_slice(identity.as_bytes());
+    }
+    message
+}
+
+fn sign(key: &Key, cookie_token: &CookieToken, identity: Option<&str>) -> Vec<u8> {
+    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, key.signing());
+    hmac::sign(&hmac_key, &signed_message(cookie_token, identity))
+        .as_ref()
+        .to_vec()
+}
+
+fn verify_signed(key: &Key, cookie_token: &CookieToken, identity: Option<&str>, submitted: &[u8]) -> bool {
+    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, key.signing());
+    hmac::verify(&hmac_key, &signed_message(cookie_token, identity), submitted).is_ok()
+}
+
+/// Extracts the XSRF token submitted via the request header, for handlers
+/// that need to validate it themselves (e.g. routes exempted from
+/// `ProtectXSRF`). Mirrors [`RequestXSRF::xsrf_token`], which instead
+/// returns the token a handler should embed in a form or response.
+pub struct XsrfHeader(pub Option<RequestToken>);
+
+impl FromRequest for XsrfHeader {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let ext = req.extensions();
+        let header_token = ext
+            .get::<ReqExt>()
+            .expect(MIDDLEWARE_MISSING)
+            .header_token
+            .clone();
+        ok(XsrfHeader(header_token))
+    }
+}
+
 pub trait RequestXSRF {
     fn xsrf_token(&self) -> RequestToken;
 }
@@ -63,17 +310,63 @@ impl RequestXSRF for HttpRequest {
 
         let mut ext = self.extensions_mut();
         let re = ext.get_mut::<ReqExt>().expect(MIDDLEWARE_MISSING);
-        let ct = re.cookie_token.as_ref().unwrap();
-        re.request_token = Some(ct.gen_req_token());
-        return re.request_token.as_ref().unwrap().clone();
+        let ct = re.cookie_token.as_ref().unwrap().clone();
+        let rt = match &re.mode {
+            TokenMode::DoubleSubmit => ct.gen_req_token(),
+            TokenMode::Signed(key) => base64::encode_config(
+                sign(key, &ct, re.identity.as_deref()),
+                base64::URL_SAFE_NO_PAD,
+            )
+            .as_str()
+            .try_into()
+            .expect("signed xsrf token should be a valid RequestToken"),
+        };
+        re.request_token = Some(rt.clone());
+        rt
     }
 }
 
 struct ReqExt {
     cookie_name: &'static str,
+    cookie_attrs: CookieAttributes,
     cookie_token: Option<CookieToken>,
     request_token: Option<RequestToken>,
+    header_token: Option<RequestToken>,
     write_cookie: bool,
+    mode: TokenMode,
+    identity: Option<String>,
+}
+
+/// Effective cookie attributes for a given `ProtectXSRF` config, after
+/// applying any `__Host-`/`__Secure-` prefix requirements from
+/// [RFC 6265bis](https://tools.ietf.org/html/draft-ietf-httpbis-rfc6265bis).
+#[derive(Clone)]
+struct CookieAttributes {
+    same_site: SameSite,
+    secure: bool,
+    http_only: bool,
+    path: &'static str,
+    domain: Option<&'static str>,
+}
+
+impl CookieAttributes {
+    fn for_config(config: &ProtectXSRF) -> Self {
+        let mut attrs = CookieAttributes {
+            same_site: config.same_site,
+            secure: config.secure,
+            http_only: config.http_only,
+            path: config.path,
+            domain: config.domain,
+        };
+        if config.cookie_name.starts_with("__Host-") {
+            attrs.secure = true;
+            attrs.path = "/";
+            attrs.domain = None;
+        } else if config.cookie_name.starts_with("__Secure-") {
+            attrs.secure = true;
+        }
+        attrs
+    }
 }
 
 /// `Middleware` to clean request's URI, and redirect if necessary.
@@ -81,6 +374,168 @@ struct ReqExt {
 #[derive(Clone)]
 pub struct ProtectXSRF {
     cookie_name: &'static str,
+    query_param_name: Option<&'static str>,
+    header_name: &'static str,
+    same_site: SameSite,
+    secure: bool,
+    http_only: bool,
+    path: &'static str,
+    domain: Option<&'static str>,
+    safe_methods: Vec<http::Method>,
+    bypass: Vec<(Option<http::Method>, PathPattern)>,
+    mode: TokenMode,
+}
+
+impl ProtectXSRF {
+    /// Starts building a `ProtectXSRF` with the crate's recommended
+    /// defaults: a `Lax`, `HttpOnly` cookie named `xsrf-token`, an
+    /// `X-CSRF-Token` header, `GET`/`HEAD`/`OPTIONS`/`TRACE` treated as
+    /// safe, and no query-parameter fallback (see
+    /// [`ProtectXSRFBuilder::query_param_name`] before enabling one).
+    pub fn builder() -> ProtectXSRFBuilder {
+        ProtectXSRFBuilder::default()
+    }
+}
+
+/// Builder for [`ProtectXSRF`]. See [`ProtectXSRF::builder`].
+pub struct ProtectXSRFBuilder {
+    cookie_name: &'static str,
+    query_param_name: Option<&'static str>,
+    header_name: &'static str,
+    same_site: SameSite,
+    secure: bool,
+    http_only: bool,
+    path: &'static str,
+    domain: Option<&'static str>,
+    safe_methods: Vec<http::Method>,
+    bypass: Vec<(Option<http::Method>, PathPattern)>,
+    mode: TokenMode,
+}
+
+impl Default for ProtectXSRFBuilder {
+    fn default() -> Self {
+        ProtectXSRFBuilder {
+            cookie_name: "xsrf-token",
+            query_param_name: None,
+            header_name: "X-CSRF-Token",
+            same_site: SameSite::Lax,
+            secure: false,
+            http_only: true,
+            path: "/",
+            domain: None,
+            safe_methods: default_safe_methods(),
+            bypass: Vec::new(),
+            mode: TokenMode::DoubleSubmit,
+        }
+    }
+}
+
+impl ProtectXSRFBuilder {
+    /// Name of the cookie holding the `CookieToken`. A `__Host-` or
+    /// `__Secure-` prefix forces the matching cookie attributes, see
+    /// [`ProtectXSRFBuilder::secure`] and [`ProtectXSRFBuilder::path`].
+    pub fn cookie_name(mut self, cookie_name: &'static str) -> Self {
+        self.cookie_name = cookie_name;
+        self
+    }
+
+    /// Enables submitting the token via a query parameter, as a fallback
+    /// when `header_name` isn't present, and sets its name.
+    ///
+    /// Disabled by default: putting the token in the URL means it can end
+    /// up in server/proxy access logs, browser history, and leak to
+    /// third-party resources the page loads via the `Referer` header — the
+    /// leaks masked double-submit cookies are meant to avoid in the first
+    /// place. Since verification runs ahead of body parsing, this crate has
+    /// no way to read a url-encoded form field out of the request body, so
+    /// there's no plain-form-POST channel that avoids the URL entirely;
+    /// prefer submitting via `header_name` (e.g. from JS, or a `<form>`
+    /// augmented to set it) and only enable this if you specifically need
+    /// to support a plain HTML form whose action URL can carry
+    /// `?{query_param_name}=...`.
+    pub fn query_param_name(mut self, query_param_name: &'static str) -> Self {
+        self.query_param_name = Some(query_param_name);
+        self
+    }
+
+    /// HTTP header holding the submitted `RequestToken`, checked before the
+    /// query parameter.
+    pub fn header_name(mut self, header_name: &'static str) -> Self {
+        self.header_name = header_name;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn path(mut self, path: &'static str) -> Self {
+        self.path = path;
+        self
+    }
+
+    pub fn domain(mut self, domain: &'static str) -> Self {
+        self.domain = Some(domain);
+        self
+    }
+
+    /// Overrides the set of methods let through without a submitted token.
+    /// Defaults to `GET`, `HEAD`, `OPTIONS`, `TRACE`.
+    pub fn safe_methods(mut self, safe_methods: Vec<http::Method>) -> Self {
+        self.safe_methods = safe_methods;
+        self
+    }
+
+    /// Registers a `(method, path)` pair that skips verification entirely,
+    /// e.g. a webhook endpoint authenticated some other way, or a login
+    /// route that issues the first token. `method` of `None` matches any
+    /// method; `path` ending in `*` matches by prefix, otherwise it must
+    /// match exactly. A cookie is still issued for bypassed requests if one
+    /// would otherwise be generated.
+    ///
+    /// This is the supported way to opt a route out while `ProtectXSRF`
+    /// stays installed via `App::wrap`; see the module docs ("Per-route
+    /// opt-out") for why a route-declared marker can't do the same thing.
+    pub fn bypass(mut self, method: Option<http::Method>, path: &'static str) -> Self {
+        self.bypass.push((method, PathPattern::parse(path)));
+        self
+    }
+
+    /// Switches to HMAC-signed tokens, replacing the default keyless
+    /// double-submit-cookie mode. `key` is the server-side secret the HMAC
+    /// is computed with; see [`XsrfIdentity`] to additionally bind tokens
+    /// to a logged-in user.
+    pub fn key(mut self, key: Key) -> Self {
+        self.mode = TokenMode::Signed(key);
+        self
+    }
+
+    pub fn build(self) -> ProtectXSRF {
+        ProtectXSRF {
+            cookie_name: self.cookie_name,
+            query_param_name: self.query_param_name,
+            header_name: self.header_name,
+            same_site: self.same_site,
+            secure: self.secure,
+            http_only: self.http_only,
+            path: self.path,
+            domain: self.domain,
+            safe_methods: self.safe_methods,
+            bypass: self.bypass,
+            mode: self.mode,
+        }
+    }
 }
 
 impl<S, B> Transform<S> for ProtectXSRF
@@ -124,18 +579,26 @@ where
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        // TODO: check for token if not whitelisted method
-        // TODO: issue token cookie, if one was used in the request
-        // TODO: extension methods on request to get request token
-        // TODO: allow bypassing check on certain paths
+        let header_token = get_header_token(&req, &self.config);
+        let identity = identity_of(&req);
+
+        if !is_bypassed(&self.config, &req) && !is_safe_method(&self.config, req.method()) {
+            if let Err(err) = verify_request(&req, &self.config) {
+                return ok(req.error_response(err)).boxed_local();
+            }
+        }
 
         {
             let mut ext = req.extensions_mut();
             ext.insert(ReqExt {
                 cookie_name: self.config.cookie_name,
+                cookie_attrs: CookieAttributes::for_config(&self.config),
                 cookie_token: None,
                 request_token: None,
+                header_token,
                 write_cookie: false,
+                mode: self.config.mode.clone(),
+                identity,
             });
         }
 
@@ -151,12 +614,19 @@ where
                 let ext = res.request().extensions();
                 let req_ext = ext.get::<ReqExt>().unwrap();
                 if req_ext.write_cookie {
-                    let cookie = CookieBuilder::new(
+                    let attrs = &req_ext.cookie_attrs;
+                    let mut builder = CookieBuilder::new(
                         req_ext.cookie_name,
                         req_ext.cookie_token.as_ref().unwrap().to_string(),
                     )
-                    .finish()
-                    .to_string();
+                    .same_site(attrs.same_site)
+                    .secure(attrs.secure)
+                    .http_only(attrs.http_only)
+                    .path(attrs.path);
+                    if let Some(domain) = attrs.domain {
+                        builder = builder.domain(domain);
+                    }
+                    let cookie = builder.finish().to_string();
                     drop(ext);
                     res.headers_mut().append(
                         http::header::SET_COOKIE,
@@ -173,7 +643,8 @@ where
 #[cfg(test)]
 mod tests {
     use super::{ProtectXSRF, RequestXSRF};
-    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::cookie::Key;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
     use actix_web::{http, web, App, HttpRequest, HttpResponse, Responder};
 
     async fn echo_request_token1(req: HttpRequest) -> impl Responder {
@@ -192,7 +663,12 @@ mod tests {
         () => {
             init_service(
                 App::new()
-                    .wrap(ProtectXSRF { cookie_name: "x" })
+                    .wrap(
+                        ProtectXSRF::builder()
+                            .cookie_name("x")
+                            .query_param_name("xsrf_token")
+                            .build(),
+                    )
                     .service(web::resource("/unused/").to(|| HttpResponse::Ok()))
                     .service(web::resource("/echo1/").to(echo_request_token1))
                     .service(web::resource("/echo2/").to(echo_request_token2)),
@@ -214,4 +690,213 @@ mod tests {
             .unwrap()
             .starts_with("x="));
     }
+
+    #[actix_rt::test]
+    async fn test_post_without_token_is_forbidden() {
+        let mut app = app!();
+        let req = TestRequest::post().uri("/echo1/").to_request();
+        let res = call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_with_mismatched_token_is_forbidden() {
+        let mut app = app!();
+        let req = TestRequest::post()
+            .uri("/echo1/?xsrf_token=not-the-right-token")
+            .cookie(http::Cookie::new("x", "also-not-a-real-token"))
+            .to_request();
+        let res = call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_with_mismatched_header_token_is_forbidden() {
+        let mut app = app!();
+        let req = TestRequest::post()
+            .uri("/echo1/")
+            .header("X-CSRF-Token", "not-the-right-token")
+            .cookie(http::Cookie::new("x", "also-not-a-real-token"))
+            .to_request();
+        let res = call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_host_prefix_forces_secure_and_root_path() {
+        let mut app = init_service(
+            App::new()
+                .wrap(
+                    ProtectXSRF::builder()
+                        .cookie_name("__Host-x")
+                        .path("/app/")
+                        .domain("example.com")
+                        .build(),
+                )
+                .service(web::resource("/echo1/").to(echo_request_token1)),
+        )
+        .await;
+        let req = TestRequest::with_uri("/echo1/").to_request();
+        let res = call_service(&mut app, req).await;
+        let cookie = res
+            .headers()
+            .get(http::header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(cookie.starts_with("__Host-x="));
+        assert!(cookie.contains("Secure"));
+        assert!(cookie.contains("Path=/"));
+        assert!(!cookie.contains("Domain"));
+    }
+
+    #[actix_rt::test]
+    async fn test_bypassed_path_skips_verification() {
+        let mut app = init_service(
+            App::new()
+                .wrap(
+                    ProtectXSRF::builder()
+                        .cookie_name("x")
+                        .bypass(None, "/webhook/*")
+                        .build(),
+                )
+                .service(web::resource("/webhook/stripe/").to(|| HttpResponse::Ok()))
+                .service(web::resource("/other/").to(|| HttpResponse::Ok())),
+        )
+        .await;
+        let req = TestRequest::post().uri("/webhook/stripe/").to_request();
+        let res = call_service(&mut app, req).await;
+        assert!(res.status().is_success());
+
+        // A path the bypass rule doesn't match still gets verified, so the
+        // rule isn't accidentally matching everything.
+        let req = TestRequest::post().uri("/other/").to_request();
+        let res = call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_signed_mode_accepts_its_own_token() {
+        let mut app = init_service(
+            App::new()
+                .wrap(
+                    ProtectXSRF::builder()
+                        .cookie_name("x")
+                        .query_param_name("xsrf_token")
+                        .key(Key::generate())
+                        .build(),
+                )
+                .service(web::resource("/echo1/").to(echo_request_token1)),
+        )
+        .await;
+
+        let req = TestRequest::with_uri("/echo1/").to_request();
+        let res = call_service(&mut app, req).await;
+        let cookie = res
+            .headers()
+            .get(http::header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        let token = read_body(res).await;
+        let token = std::str::from_utf8(&token).unwrap();
+
+        let req = TestRequest::post()
+            .uri(&format!("/echo1/?xsrf_token={}", token))
+            .header(http::header::COOKIE, cookie)
+            .to_request();
+        let res = call_service(&mut app, req).await;
+        assert!(res.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_signed_mode_rejects_token_from_a_different_key() {
+        let mut app_a = init_service(
+            App::new()
+                .wrap(
+                    ProtectXSRF::builder()
+                        .cookie_name("x")
+                        .query_param_name("xsrf_token")
+                        .key(Key::generate())
+                        .build(),
+                )
+                .service(web::resource("/echo1/").to(echo_request_token1)),
+        )
+        .await;
+        let mut app_b = init_service(
+            App::new()
+                .wrap(
+                    ProtectXSRF::builder()
+                        .cookie_name("x")
+                        .query_param_name("xsrf_token")
+                        .key(Key::generate())
+                        .build(),
+                )
+                .service(web::resource("/echo1/").to(echo_request_token1)),
+        )
+        .await;
+
+        let req = TestRequest::with_uri("/echo1/").to_request();
+        let res = call_service(&mut app_a, req).await;
+        let cookie = res
+            .headers()
+            .get(http::header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        let token = read_body(res).await;
+        let token = std::str::from_utf8(&token).unwrap();
+
+        // Token and cookie were minted under key A; submit them to an app
+        // configured with key B.
+        let req = TestRequest::post()
+            .uri(&format!("/echo1/?xsrf_token={}", token))
+            .header(http::header::COOKIE, cookie)
+            .to_request();
+        let res = call_service(&mut app_b, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_missing_cookie_response_body_describes_the_error() {
+        let mut app = app!();
+        let req = TestRequest::post().uri("/echo1/").to_request();
+        let res = call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+        let body = read_body(res).await;
+        assert_eq!(&body[..], b"missing xsrf cookie");
+    }
+
+    #[actix_rt::test]
+    async fn test_resource_level_wrap_protects_only_that_route() {
+        // A login route that issues the first token is simply left
+        // unwrapped, rather than relying on a marker the app-level
+        // `ProtectXSRF` would have no way to see ahead of routing.
+        let mut app = init_service(
+            App::new()
+                .service(web::resource("/login/").to(|| HttpResponse::Ok()))
+                .service(
+                    web::resource("/echo1/")
+                        .wrap(ProtectXSRF::builder().cookie_name("x").build())
+                        .to(echo_request_token1),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::post().uri("/login/").to_request();
+        let res = call_service(&mut app, req).await;
+        assert!(res.status().is_success());
+
+        let req = TestRequest::post().uri("/echo1/").to_request();
+        let res = call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+    }
 }